@@ -1,16 +1,24 @@
 use codec::CoapCodec;
+use dtls;
 use Endpoint;
 use error::Error;
-use message::{Message, Code};
-use message::option::{Option, Options, UriPath, UriHost, UriQuery};
+use message::{Message, Code, Type as MessageType};
+use message::option::{Option, Options, UriPath, UriHost, UriQuery, ContentFormat, Block1, Block2, Observe};
 
 use std::borrow::Cow;
+use std::mem;
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant};
 
+use futures::future::{self, Loop};
 use futures::prelude::*;
+use futures::stream;
 
+use rand::Rng;
+
+use tokio::executor::{DefaultExecutor, Executor};
 use tokio::net::{UdpSocket, UdpFramed};
+use tokio::timer::Delay;
 use tokio::util::FutureExt;
 
 use percent_encoding::percent_decode;
@@ -19,11 +27,99 @@ use uri::Uri;
 /// An alias for the futures produced by this library.
 pub type IoFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
 
+/// RFC 7252 ยง4.8: initial timeout before the first retransmission of a
+/// Confirmable message.
+const ACK_TIMEOUT_MS: u64 = 2000;
+/// RFC 7252 ยง4.8: the initial timeout is multiplied by a random factor
+/// drawn uniformly from `[1.0, ACK_RANDOM_FACTOR)` to avoid synchronized
+/// retransmissions across clients.
+const ACK_RANDOM_FACTOR: f64 = 1.5;
+/// RFC 7252 ยง4.8: number of retransmissions attempted before giving up.
+const MAX_RETRANSMIT: u32 = 4;
+/// RFC 7252 ยง4.8.2: upper bound on how long a Confirmable exchange may take
+/// to complete, including all retransmissions.
+const EXCHANGE_LIFETIME_MS: u64 = 247_000;
+/// Number of random bytes used for the Token of each outgoing request.
+const TOKEN_LEN: usize = 4;
+/// RFC 7959 ยง4: the largest block size we'll offer/request by default.
+const DEFAULT_BLOCK_SIZE: usize = 1024;
+/// RFC 7641 ยง3.4: Observe sequence numbers are compared modulo 2^24.
+const OBSERVE_WRAP_THRESHOLD: u32 = 1 << 23;
+/// RFC 7641 ยง3.4: how long a sequence number comparison stays meaningful
+/// before any freshly arrived notification is just accepted.
+const OBSERVE_FRESHNESS_MS: u64 = 128_000;
+
+/// The RFC 7252 ยง4.8 transmission parameters, threaded through a whole
+/// exchange (including the follow-up requests of a block-wise transfer).
+#[derive(Clone, Copy)]
+struct RetransmitParams {
+    ack_timeout: Duration,
+    ack_random_factor: f64,
+    max_retransmit: u32,
+    exchange_lifetime: Duration,
+}
+
+/// Whether an exchange needs the DTLS-secured `coaps` transport, and the
+/// credentials to use if so.
+#[derive(Clone)]
+struct TransportConfig {
+    secure: bool,
+    security: Security,
+}
+
+/// Generate a random Token to correlate a request with its response,
+/// independently of the Message ID (RFC 7252 ยง5.3.1).
+fn generate_token() -> Vec<u8> {
+    let mut token = vec![0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut token);
+    token
+}
+
+/// A fresh 16-bit Message ID for a newly transmitted datagram (RFC 7252
+/// ยง3). Only true retransmissions of the same datagram reuse an ID.
+fn generate_message_id() -> u16 {
+    rand::thread_rng().gen()
+}
+
+/// Credentials used to secure a `coaps://` exchange with DTLS (RFC 7252
+/// ยง9). Defaults to `None`, i.e. the plain `coap://` transport.
+#[derive(Clone)]
+pub enum Security {
+    /// No DTLS; only valid for `coap://` endpoints.
+    None,
+    /// Pre-shared key: a client identity hint and the shared secret
+    /// (RFC 7925 ยง2.1 / RFC 4279).
+    Psk { identity: Vec<u8>, key: Vec<u8> },
+    /// Raw public key or X.509 certificate and its private key, handed to
+    /// the DTLS implementation as-is.
+    Certificate { cert: Vec<u8>, key: Vec<u8> },
+}
+
+impl Default for Security {
+    fn default() -> Security {
+        Security::None
+    }
+}
+
 pub struct Client {
     /// the remote endpoint to contact
     endpoint: Endpoint,
     /// the message to be sent
     msg: Message,
+    /// initial timeout before the first retransmission of a CON message
+    ack_timeout: Duration,
+    /// random factor applied to `ack_timeout`, must be >= 1.0
+    ack_random_factor: f64,
+    /// number of retransmissions attempted before a CON message is given up on
+    max_retransmit: u32,
+    /// how long to keep waiting for a separate response after the ACK arrives
+    exchange_lifetime: Duration,
+    /// preferred block size (bytes) used to negotiate block-wise transfers
+    block_size: usize,
+    /// DTLS credentials used when the endpoint requires `coaps://`
+    security: Security,
+    /// whether the target URI required the DTLS-secured `coaps` scheme
+    secure: bool,
 }
 
 fn depercent(s: &str) -> Result<String, Error> {
@@ -34,15 +130,18 @@ fn depercent(s: &str) -> Result<String, Error> {
 }
 
 /// RFC 7252: 6.4.  Decomposing URIs into Options
-fn decompose(uri: Uri) -> Result<(Endpoint, Options), Error> {
+///
+/// Returns whether the URI requires the DTLS-secured `coaps` transport
+/// alongside the endpoint (resolved only for a literal IP host) and options.
+fn decompose(uri: Uri) -> Result<(Endpoint, Options, bool), Error> {
     let mut options = Options::new();
 
-    // Step 3, TODO: Support coaps
-    match &*uri.scheme {
-        "coap" => (),
-        "coaps" => Err(Error::url_parsing("the coaps scheme is currently unsupported"))?,
+    // Step 3
+    let secure = match &*uri.scheme {
+        "coap" => false,
+        "coaps" => true,
         other => Err(Error::url_parsing(format!("{} is not a coap scheme", other)))?,
-    }
+    };
 
     // Step 4
     if uri.fragment.is_some() {
@@ -58,7 +157,7 @@ fn decompose(uri: Uri) -> Result<(Endpoint, Options), Error> {
     }
 
     // Step 6
-    let port = uri.port.unwrap_or(5683);
+    let port = uri.port.unwrap_or(if secure { 5684 } else { 5683 });
 
     // Step 7 & 8
     let path = uri.path.unwrap_or("/".to_owned());
@@ -78,28 +177,58 @@ fn decompose(uri: Uri) -> Result<(Endpoint, Options), Error> {
     }
 
     if let Some(ip) = ip {
-        Ok((Endpoint::Resolved(SocketAddr::new(ip, port)), options))
+        Ok((Endpoint::Resolved(SocketAddr::new(ip, port)), options, secure))
     } else {
-        Ok((Endpoint::Unresolved(host, port), options))
+        Ok((Endpoint::Unresolved(host, port), options, secure))
     }
 }
 
 impl Client {
     pub fn new() -> Client {
+        let mut msg = Message::new();
+        msg.token = generate_token();
+
         Client {
             endpoint: Endpoint::Unset,
-            msg: Message::new(),
+            msg,
+            ack_timeout: Duration::from_millis(ACK_TIMEOUT_MS),
+            ack_random_factor: ACK_RANDOM_FACTOR,
+            max_retransmit: MAX_RETRANSMIT,
+            exchange_lifetime: Duration::from_millis(EXCHANGE_LIFETIME_MS),
+            block_size: DEFAULT_BLOCK_SIZE,
+            security: Security::None,
+            secure: false,
         }
     }
 
     pub fn get(url: &str) -> Result<Client, Error> {
+        Client::request(Code::Get, url)
+    }
+
+    pub fn post(url: &str) -> Result<Client, Error> {
+        Client::request(Code::Post, url)
+    }
+
+    pub fn put(url: &str) -> Result<Client, Error> {
+        Client::request(Code::Put, url)
+    }
+
+    pub fn delete(url: &str) -> Result<Client, Error> {
+        Client::request(Code::Delete, url)
+    }
+
+    /// Build a request of the given method against `url`, decomposing it
+    /// into the equivalent Uri-* options (RFC 7252 ยง6.4).
+    pub fn request(code: Code, url: &str) -> Result<Client, Error> {
         let mut client = Client::new();
         let url = Uri::new(url).map_err(Error::url_parsing)?;
 
-        let (endpoint, options) = decompose(url)?;
+        let (endpoint, options, secure) = decompose(url)?;
 
         client.set_endpoint(endpoint);
         client.msg.options = options;
+        client.msg.code = code;
+        client.secure = secure;
 
         Ok(client)
     }
@@ -114,52 +243,718 @@ impl Client {
         self
     }
 
+    /// Set the DTLS credentials used to secure a `coaps://` exchange.
+    pub fn set_security(&mut self, security: Security) {
+        self.security = security;
+    }
+
+    pub fn with_security(mut self, security: Security) -> Self {
+        self.set_security(security);
+
+        self
+    }
+
+    /// Set the request payload, e.g. for `POST`/`PUT`.
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.msg.payload = payload;
+    }
+
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.set_payload(payload);
+
+        self
+    }
+
+    /// Set the Content-Format option describing the media type of the
+    /// request payload (RFC 7252 ยง5.10.3).
+    pub fn set_content_format(&mut self, content_format: u16) {
+        self.msg.options.push(ContentFormat::new(content_format));
+    }
+
+    pub fn with_content_format(mut self, content_format: u16) -> Self {
+        self.set_content_format(content_format);
+
+        self
+    }
+
+    /// Set the initial ACK_TIMEOUT used for the first retransmission of a
+    /// Confirmable message. Defaults to the RFC 7252 recommended 2 s.
+    pub fn set_ack_timeout(&mut self, ack_timeout: Duration) {
+        self.ack_timeout = ack_timeout;
+    }
+
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.set_ack_timeout(ack_timeout);
+
+        self
+    }
+
+    /// Set the ACK_RANDOM_FACTOR used to jitter the initial timeout. Must be
+    /// >= 1.0. Defaults to the RFC 7252 recommended 1.5.
+    pub fn set_ack_random_factor(&mut self, ack_random_factor: f64) {
+        self.ack_random_factor = ack_random_factor;
+    }
+
+    pub fn with_ack_random_factor(mut self, ack_random_factor: f64) -> Self {
+        self.set_ack_random_factor(ack_random_factor);
+
+        self
+    }
+
+    /// Set MAX_RETRANSMIT, the number of retransmissions attempted for a CON
+    /// message before giving up. Defaults to the RFC 7252 recommended 4.
+    pub fn set_max_retransmit(&mut self, max_retransmit: u32) {
+        self.max_retransmit = max_retransmit;
+    }
+
+    pub fn with_max_retransmit(mut self, max_retransmit: u32) -> Self {
+        self.set_max_retransmit(max_retransmit);
+
+        self
+    }
+
+    /// Set EXCHANGE_LIFETIME, how long to keep waiting for a separate
+    /// response once the ACK for a CON message has arrived.
+    pub fn set_exchange_lifetime(&mut self, exchange_lifetime: Duration) {
+        self.exchange_lifetime = exchange_lifetime;
+    }
+
+    pub fn with_exchange_lifetime(mut self, exchange_lifetime: Duration) -> Self {
+        self.set_exchange_lifetime(exchange_lifetime);
+
+        self
+    }
+
+    /// Set the preferred block size (bytes) used to negotiate block-wise
+    /// transfers (RFC 7959). Must be a power of two between 16 and 1024.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        assert!(
+            block_size.is_power_of_two() && block_size >= 16 && block_size <= 1024,
+            "block_size must be a power of two between 16 and 1024, got {}",
+            block_size
+        );
+        self.block_size = block_size;
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.set_block_size(block_size);
+
+        self
+    }
+
     pub fn send(self) -> IoFuture<Message> {
-        let local_addr = "0.0.0.0:0".parse().unwrap();
-
-        let Self { endpoint, msg } = self;
-        let client_request = endpoint
-            .resolve()
-            .and_then(move |remote_addr| {
-                let sock = UdpSocket::bind(&local_addr).unwrap();
-
-                let framed_socket = UdpFramed::new(sock, CoapCodec);
-
-                info!("sending request");
-                let client =  framed_socket
-                    .send((msg, remote_addr))
-                    .and_then(|sock| {
-                        let timeout_time = Instant::now() + Duration::from_millis(5000);
-                        sock
-                            .filter_map(|(msg, _addr)| {
-                                match msg.code {
-                                    Code::Content => {
-                                        Some(msg)
-                                    },
-                                    _ => {
-                                        warn!("Unexpeted Response");
-                                        None
-                                    },
-                                }
-                            })
-                            .take(1)
-                            .collect()
-                            .map(|mut list| {
-                                list.pop().expect("list of one somehow had nothing to pop")
-                            })
-                            .deadline(timeout_time)
-                            .map_err(|e| { println!("{:?}", e); Error::Timeout })
-                    });
-
-                client
+        let Self { endpoint, msg, ack_timeout, ack_random_factor, max_retransmit, exchange_lifetime, block_size, secure, security } = self;
+        let params = RetransmitParams { ack_timeout, ack_random_factor, max_retransmit, exchange_lifetime };
+        let transport = TransportConfig { secure, security };
+
+        if msg.payload.len() > block_size {
+            // RFC 7959 ยง3: the request payload doesn't fit in one datagram,
+            // split it into Block1 chunks and send them sequentially.
+            return send_block1(endpoint, msg, block_size, params, transport);
+        }
+
+        let base_msg = msg.clone();
+        Box::new(
+            send_once_with_transport(endpoint, msg, params, transport)
+                .and_then(move |(response, sock, remote_addr)| {
+                    reassemble_block2(sock, remote_addr, base_msg, response, block_size, params)
+                })
+        )
+    }
+
+    /// Register for change notifications on a resource (RFC 7641). The
+    /// registration GET goes through the same CON retransmission machinery
+    /// as any other request, so a lost registration is retried rather than
+    /// hanging forever. Each notification is emitted as a stream item;
+    /// dropping the stream sends a best-effort deregistration GET.
+    pub fn observe(self) -> Box<Stream<Item = Message, Error = Error> + Send> {
+        let Self { endpoint, msg, ack_timeout, ack_random_factor, max_retransmit, exchange_lifetime, secure, security, .. } = self;
+        let params = RetransmitParams { ack_timeout, ack_random_factor, max_retransmit, exchange_lifetime };
+        let transport = TransportConfig { secure, security };
+        let deregister_transport = transport.clone();
+
+        let base_msg = msg.clone();
+        let token = msg.token.clone();
+        let mut register_msg = msg;
+        register_msg.options.push(Observe::new(0));
+
+        // The registration exchange's response is itself the first
+        // notification (it carries the current state and Observe
+        // sequence 0), so it's yielded rather than discarded.
+        let stream = send_once_with_transport(endpoint, register_msg, params, transport)
+            .map(move |(first, sock, remote_addr)| {
+                let notifications = ObserveStream::new(sock, base_msg, token, remote_addr, deregister_transport);
+                stream::once(Ok(first)).chain(notifications)
+            });
+
+        Box::new(stream.flatten_stream())
+    }
+}
+
+fn duration_mul_f64(duration: Duration, factor: f64) -> Duration {
+    let nanos = (duration.as_secs() as f64 * 1e9 + duration.subsec_nanos() as f64) * factor;
+    Duration::from_millis((nanos / 1e6) as u64)
+}
+
+/// Resolve the endpoint, bind a fresh socket and drive a single CoAP
+/// request/response exchange: retransmitted on a backoff if `msg` is CON
+/// (RFC 7252 ยง4.2), sent once otherwise. Hands the transport and the
+/// address it was reached at back alongside the response so long-lived
+/// callers (e.g. `observe`) can keep using it.
+fn send_once_with_transport(endpoint: Endpoint, msg: Message, params: RetransmitParams, transport: TransportConfig) -> Box<Future<Item = (Message, Box<Transport>, SocketAddr), Error = Error> + Send> {
+    Box::new(endpoint
+        .resolve()
+        .and_then(move |remote_addr| {
+            info!("sending request");
+
+            connect(remote_addr, transport)
+                .and_then(move |sock| send_on_transport(sock, msg, remote_addr, params))
+                .map(move |(msg, sock)| (msg, sock, remote_addr))
+        })
+    )
+}
+
+/// Drive a single request/response exchange on an already-connected `sock`,
+/// retransmitted on a backoff if `msg` is CON (RFC 7252 ยง4.2). Hands `sock`
+/// back alongside the response so block-wise callers can reuse the same
+/// transport for the next block instead of reconnecting (and, for
+/// `coaps://`, re-handshaking) on every block.
+fn send_on_transport(sock: Box<Transport>, msg: Message, remote_addr: SocketAddr, params: RetransmitParams) -> Box<Future<Item = (Message, Box<Transport>), Error = Error> + Send> {
+    let confirmable = msg.mtype == MessageType::Con;
+    let token = msg.token.clone();
+
+    let RetransmitParams { ack_timeout, ack_random_factor, max_retransmit, exchange_lifetime } = params;
+
+    if !confirmable {
+        // NON: fire-and-forget, wait once for the response up to
+        // EXCHANGE_LIFETIME.
+        let timeout_time = Instant::now() + exchange_lifetime;
+        return Box::new(sock
+            .send((msg, remote_addr))
+            .and_then(move |sock| wait_for_response(sock, token, remote_addr))
+            .deadline(timeout_time)
+            .map_err(|e| { warn!("{:?}", e); Error::Timeout }));
+    }
+
+    // RFC 7252 ยง4.2: retransmit a Confirmable message on a binary
+    // exponential backoff, starting from a jittered ACK_TIMEOUT, doubling
+    // on every retry, until a matching ACK/response arrives or
+    // MAX_RETRANSMIT retransmissions are exhausted.
+    let factor = if ack_random_factor > 1.0 {
+        rand::thread_rng().gen_range(1.0, ack_random_factor)
+    } else {
+        1.0
+    };
+    let initial_timeout = duration_mul_f64(ack_timeout, factor);
+
+    Box::new(ConfirmableSend::new(
+        sock,
+        msg,
+        token,
+        remote_addr,
+        initial_timeout,
+        max_retransmit,
+        exchange_lifetime,
+    ))
+}
+
+/// Bind a local socket and prepare the transport to `remote_addr`,
+/// performing a DTLS handshake first when `transport.secure` requires it
+/// (RFC 7252 ยง9 `coaps`).
+fn connect(remote_addr: SocketAddr, transport: TransportConfig) -> IoFuture<Box<Transport>> {
+    let local_addr = "0.0.0.0:0".parse().unwrap();
+    let sock = UdpSocket::bind(&local_addr).unwrap();
+
+    if !transport.secure {
+        return Box::new(future::ok(Box::new(UdpFramed::new(sock, CoapCodec)) as Box<Transport>));
+    }
+
+    if let Security::None = transport.security {
+        return Box::new(future::err(Error::url_parsing(
+            "coaps:// requires a Security configuration, see Client::with_security"
+        )));
+    }
+
+    Box::new(
+        dtls::handshake(sock, remote_addr, transport.security, CoapCodec)
+            .map(|secured| Box::new(secured) as Box<Transport>)
+    )
+}
+
+/// Unifies the plain UDP and DTLS-secured transports behind one type, so
+/// the rest of the client doesn't need to care which one it negotiated.
+trait Transport: Stream<Item = (Message, SocketAddr), Error = Error> + Sink<SinkItem = (Message, SocketAddr), SinkError = Error> + Send {}
+
+impl<T> Transport for T
+where
+    T: Stream<Item = (Message, SocketAddr), Error = Error> + Sink<SinkItem = (Message, SocketAddr), SinkError = Error> + Send
+{}
+
+/// Whether the server's echoed Block1 option matches the block we just
+/// sent: same NUM, same negotiated size. RFC 7959 ยง2.5 lets a server
+/// downsize the block on us mid-transfer; if it does, NUM and offset would
+/// desync on the next block, so the transfer must be aborted instead of
+/// silently continuing as if nothing changed.
+fn block1_matches(block: &Block1, expected_num: u32, expected_size: usize) -> bool {
+    block.num() == expected_num && block.size() == expected_size
+}
+
+/// RFC 7959 ยง3: split an oversized request payload into Block1 chunks and
+/// send them one at a time, only surfacing the final exchange's response.
+///
+/// Resolves and connects once, then reuses that same transport for every
+/// block: reconnecting per block would mean a fresh DTLS handshake per
+/// ~1KB chunk on `coaps://`, defeating the point of pairing block-wise
+/// transfer with a secured transport.
+fn send_block1(endpoint: Endpoint, msg: Message, block_size: usize, params: RetransmitParams, transport: TransportConfig) -> IoFuture<Message> {
+    let chunks: Vec<Vec<u8>> = msg.payload.chunks(block_size).map(<[u8]>::to_vec).collect();
+    let total = chunks.len();
+
+    Box::new(endpoint.resolve().and_then(move |remote_addr| {
+        connect(remote_addr, transport).and_then(move |sock| {
+            future::loop_fn((sock, 0usize), move |(sock, num)| {
+                let mut chunk_msg = msg.clone();
+                // RFC 7252 ยง4.4: each transmitted datagram gets its own
+                // Message ID; only retries of the very same datagram reuse
+                // one. Reusing `msg`'s ID across blocks would let a
+                // compliant server treat block 2+ as a duplicate of block 1
+                // and just resend its cached reply.
+                chunk_msg.id = generate_message_id();
+                chunk_msg.payload = chunks[num].clone();
+                let more = num + 1 < total;
+                chunk_msg.options.push(Block1::new(num as u32, more, block_size));
+
+                send_on_transport(sock, chunk_msg, remote_addr, params)
+                    .and_then(move |(response, sock)| {
+                        if response.code.is_error() {
+                            warn!("block-wise upload rejected: {:?}", response.code);
+                            return Err(Error::Timeout);
+                        }
+
+                        if !more {
+                            return Ok(Loop::Break(response));
+                        }
+
+                        match response.options.get::<Block1>() {
+                            Some(ref echoed) if block1_matches(echoed, num as u32, block_size) => {
+                                Ok(Loop::Continue((sock, num + 1)))
+                            },
+                            Some(_) => {
+                                warn!("block-wise upload NUM/size mismatch, aborting");
+                                Err(Error::Timeout)
+                            },
+                            None => {
+                                warn!("server stopped acknowledging Block1 mid-transfer");
+                                Err(Error::Timeout)
+                            },
+                        }
+                    })
+            })
+        })
+    }))
+}
+
+/// Whether a follow-up Block2 response is the one we asked for: same NUM,
+/// same negotiated size. A mismatch on either means a gap or a server that
+/// changed the block size mid-transfer, and the transfer must be aborted.
+fn block2_matches(block: &Block2, expected_num: u32, expected_size: usize) -> bool {
+    block.num() == expected_num && block.size() == expected_size
+}
+
+/// RFC 7959 ยง2: if the response carries a Block2 option with the "more" bit
+/// set, transparently fetch the remaining blocks and concatenate their
+/// payloads, validating that each one's NUM/size matches what we asked for.
+///
+/// Takes the transport that `first` arrived on and reuses it for every
+/// follow-up block rather than reconnecting: reconnecting per block would
+/// mean a fresh DTLS handshake per ~1KB chunk on `coaps://`, defeating the
+/// point of pairing block-wise transfer with a secured transport.
+fn reassemble_block2(sock: Box<Transport>, remote_addr: SocketAddr, base_msg: Message, first: Message, block_size: usize, params: RetransmitParams)
+    -> IoFuture<Message>
+{
+    let block = match first.options.get::<Block2>() {
+        Some(block) => block,
+        None => return Box::new(future::ok(first)),
+    };
+
+    if !block.more() {
+        return Box::new(future::ok(first));
+    }
+
+    let next_num = block.num() + 1;
+    // Follow-up blocks use the size the server picked for the first one.
+    let block_size = block.size();
+
+    Box::new(future::loop_fn((sock, first, next_num), move |(sock, mut acc, num)| {
+        let mut next_msg = base_msg.clone();
+        // RFC 7252 ยง4.4: a fresh datagram gets a fresh Message ID, even
+        // though it continues the same logical request.
+        next_msg.id = generate_message_id();
+        next_msg.payload = Vec::new();
+        next_msg.options.push(Block2::new(num, false, block_size));
+
+        send_on_transport(sock, next_msg, remote_addr, params)
+            .and_then(move |(response, sock)| {
+                if response.code.is_error() {
+                    warn!("block-wise transfer aborted by server: {:?}", response.code);
+                    return Err(Error::Timeout);
+                }
+
+                match response.options.get::<Block2>() {
+                    Some(ref block) if block2_matches(block, num, block_size) => {
+                        acc.payload.extend_from_slice(&response.payload);
+                        if block.more() {
+                            Ok(Loop::Continue((sock, acc, num + 1)))
+                        } else {
+                            Ok(Loop::Break(acc))
+                        }
+                    },
+                    Some(_) => {
+                        warn!("block-wise transfer NUM/size mismatch, aborting");
+                        Err(Error::Timeout)
+                    },
+                    None => {
+                        warn!("server stopped sending Block2 mid-transfer");
+                        Err(Error::Timeout)
+                    },
+                }
+            })
+    }))
+}
+
+/// Drives an RFC 7641 observation: yields every fresh notification received
+/// on `sock` for our Token, and sends a best-effort deregistration GET when
+/// dropped.
+struct ObserveStream {
+    sock: Box<Transport>,
+    /// the original GET, without any Observe option, reused to build the
+    /// deregistration request
+    base_msg: Message,
+    token: Vec<u8>,
+    remote_addr: SocketAddr,
+    last_seq: ::std::option::Option<(u32, Instant)>,
+    /// how to reach `remote_addr` again for the deregistration request
+    transport: TransportConfig,
+}
+
+impl ObserveStream {
+    fn new(sock: Box<Transport>, base_msg: Message, token: Vec<u8>, remote_addr: SocketAddr, transport: TransportConfig) -> ObserveStream {
+        ObserveStream {
+            sock,
+            base_msg,
+            token,
+            remote_addr,
+            last_seq: None,
+            transport,
+        }
+    }
+
+    /// RFC 7641 ยง3.4: a new Observe sequence number `seq` is fresher than
+    /// the last accepted one if `seq < last && last - seq > 2^23` or
+    /// `seq > last && seq - last < 2^23`, within a 128 s freshness window
+    /// after which any value is accepted.
+    fn accept_sequence(&mut self, seq: u32) -> bool {
+        let fresh = match self.last_seq {
+            None => true,
+            Some((last, observed_at)) => {
+                observed_at.elapsed() > Duration::from_millis(OBSERVE_FRESHNESS_MS)
+                    || (seq < last && last - seq > OBSERVE_WRAP_THRESHOLD)
+                    || (seq > last && seq - last < OBSERVE_WRAP_THRESHOLD)
+            },
+        };
+
+        if fresh {
+            self.last_seq = Some((seq, Instant::now()));
+        }
+
+        fresh
+    }
+}
+
+impl Stream for ObserveStream {
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<::std::option::Option<Message>, Error> {
+        loop {
+            match self.sock.poll().map_err(|e| { warn!("{:?}", e); Error::Timeout })? {
+                Async::Ready(Some((msg, addr))) => {
+                    if addr != self.remote_addr || msg.token != self.token {
+                        continue;
+                    }
+
+                    // A bare empty ACK just confirms an earlier CON
+                    // notification; it isn't a notification itself.
+                    if msg.mtype == MessageType::Ack && msg.code == Code::Empty {
+                        continue;
+                    }
+
+                    if let Some(observe) = msg.options.get::<Observe>() {
+                        if !self.accept_sequence(observe.sequence()) {
+                            continue;
+                        }
+                    }
+
+                    if msg.mtype == MessageType::Con {
+                        // RFC 7641: ACK every Confirmable notification, or a
+                        // correct server will retransmit it and eventually
+                        // give up on and deregister us.
+                        let mut ack = Message::new();
+                        ack.mtype = MessageType::Ack;
+                        ack.code = Code::Empty;
+                        ack.id = msg.id;
+                        let _ = self.sock.start_send((ack, addr));
+                        let _ = self.sock.poll_complete();
+                    }
+
+                    return Ok(Async::Ready(Some(msg)));
+                },
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
             }
-        );
+        }
+    }
+}
 
-        Box::new(client_request)
+impl Drop for ObserveStream {
+    fn drop(&mut self) {
+        let mut deregister = self.base_msg.clone();
+        deregister.id = generate_message_id();
+        deregister.options.push(Observe::new(1));
+
+        let remote_addr = self.remote_addr;
+        let transport = self.transport.clone();
+        let task = future::lazy(move || {
+            connect(remote_addr, transport)
+                .and_then(move |sock| sock.send((deregister, remote_addr)).map_err(|e| { warn!("{:?}", e); Error::Timeout }))
+                .map(|_sock| ())
+                .map_err(|e| warn!("failed to deregister observe: {:?}", e))
+        });
+
+        // Best-effort: there's no runtime to spawn onto outside of a Tokio
+        // executor (e.g. a plain `#[test]` dropping an `ObserveStream`), and
+        // failing to deregister isn't worth panicking the caller over.
+        let _ = DefaultExecutor::current().spawn(Box::new(task));
     }
 }
 
+/// Wait for the first datagram on `sock` that came from `remote_addr` and
+/// carries the Token we sent, regardless of its response `Code` so callers
+/// can inspect error responses (e.g. `4.04 Not Found`) themselves. Hands
+/// `sock` back alongside the response so the caller can keep using it.
+fn wait_for_response<S>(sock: S, token: Vec<u8>, remote_addr: SocketAddr) -> Box<Future<Item = (Message, S), Error = Error> + Send>
+where
+    S: Stream<Item = (Message, SocketAddr), Error = Error> + Send + 'static,
+{
+    Box::new(
+        sock.filter(move |&(ref msg, addr)| addr == remote_addr && msg.token == token)
+            .into_future()
+            .map_err(|(e, _sock)| e)
+            .and_then(|(item, filtered)| {
+                item.map(|(msg, _addr)| (msg, filtered.into_inner())).ok_or(Error::Timeout)
+            })
+    )
+}
+
+/// Drives a single Confirmable exchange: send the request, wait up to the
+/// current timeout for a matching response, and on timeout retransmit with
+/// the timeout doubled, up to `max_retransmit` times (RFC 7252 ยง4.2).
+struct ConfirmableSend {
+    remote_addr: SocketAddr,
+    msg: Message,
+    token: Vec<u8>,
+    max_retransmit: u32,
+    retries: u32,
+    timeout: Duration,
+    /// RFC 7252 ยง4.2: once the empty ACK for a separate response has been
+    /// seen, retransmission stops and we just wait out `exchange_lifetime`
+    /// for the real response instead of resending the request.
+    acked: bool,
+    exchange_lifetime: Duration,
+    state: ConfirmableSendState,
+}
+
+enum ConfirmableSendState {
+    Sending(Box<Future<Item = Box<Transport>, Error = Error> + Send>),
+    Waiting(Box<Transport>, Delay),
+    Empty,
+}
+
+impl ConfirmableSend {
+    fn new(
+        sock: Box<Transport>,
+        msg: Message,
+        token: Vec<u8>,
+        remote_addr: SocketAddr,
+        initial_timeout: Duration,
+        max_retransmit: u32,
+        exchange_lifetime: Duration,
+    ) -> ConfirmableSend {
+        let send = send_request(sock, msg.clone(), remote_addr);
+        ConfirmableSend {
+            remote_addr,
+            msg,
+            token,
+            max_retransmit,
+            retries: 0,
+            timeout: initial_timeout,
+            acked: false,
+            exchange_lifetime,
+            state: ConfirmableSendState::Sending(send),
+        }
+    }
+}
+
+fn send_request(sock: Box<Transport>, msg: Message, remote_addr: SocketAddr)
+    -> Box<Future<Item = Box<Transport>, Error = Error> + Send>
+{
+    Box::new(sock.send((msg, remote_addr)).map_err(|e| { warn!("{:?}", e); Error::Timeout }))
+}
+
+impl Future for ConfirmableSend {
+    type Item = (Message, Box<Transport>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(Message, Box<Transport>), Error> {
+        loop {
+            match mem::replace(&mut self.state, ConfirmableSendState::Empty) {
+                ConfirmableSendState::Sending(mut send) => {
+                    match send.poll()? {
+                        Async::Ready(sock) => {
+                            let deadline = Delay::new(Instant::now() + self.timeout);
+                            self.state = ConfirmableSendState::Waiting(sock, deadline);
+                        },
+                        Async::NotReady => {
+                            self.state = ConfirmableSendState::Sending(send);
+                            return Ok(Async::NotReady);
+                        },
+                    }
+                },
+                ConfirmableSendState::Waiting(mut sock, mut delay) => {
+                    match sock.poll().map_err(|e| { warn!("{:?}", e); Error::Timeout })? {
+                        Async::Ready(Some((msg, addr))) => {
+                            // RFC 7252 ยง4.1: every Empty message has TKL=0,
+                            // so a bare ACK carries no Token at all and must
+                            // be matched on Message ID instead; the eventual
+                            // separate response still carries our Token and
+                            // is matched the usual way.
+                            if addr == self.remote_addr && msg.mtype == MessageType::Ack && msg.code == Code::Empty && msg.id == self.msg.id {
+                                // RFC 7252 ยง4.2: the real response is
+                                // separate and hasn't arrived yet. Stop
+                                // retransmitting, but keep waiting for it
+                                // up to EXCHANGE_LIFETIME.
+                                self.acked = true;
+                                delay = Delay::new(Instant::now() + self.exchange_lifetime);
+                                self.state = ConfirmableSendState::Waiting(sock, delay);
+                                continue;
+                            }
+                            if addr == self.remote_addr && msg.token == self.token {
+                                return Ok(Async::Ready((msg, sock)));
+                            }
+                            // Not our exchange, keep waiting on the same deadline.
+                            self.state = ConfirmableSendState::Waiting(sock, delay);
+                        },
+                        Async::Ready(None) => return Err(Error::Timeout),
+                        Async::NotReady => {
+                            match delay.poll().map_err(|e| { warn!("{:?}", e); Error::Timeout })? {
+                                Async::Ready(()) => {
+                                    if self.acked || self.retries >= self.max_retransmit {
+                                        return Err(Error::Timeout);
+                                    }
+                                    self.retries += 1;
+                                    self.timeout *= 2;
+                                    self.state = ConfirmableSendState::Sending(
+                                        send_request(sock, self.msg.clone(), self.remote_addr)
+                                    );
+                                },
+                                Async::NotReady => {
+                                    self.state = ConfirmableSendState::Waiting(sock, delay);
+                                    return Ok(Async::NotReady);
+                                },
+                            }
+                        },
+                    }
+                },
+                ConfirmableSendState::Empty => unreachable!("polled ConfirmableSend after completion"),
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn duration_mul_f64_scales_by_factor() {
+        assert_eq!(duration_mul_f64(Duration::from_millis(1000), 1.5), Duration::from_millis(1500));
+        assert_eq!(duration_mul_f64(Duration::from_millis(2000), 1.0), Duration::from_millis(2000));
+        assert_eq!(duration_mul_f64(Duration::from_millis(500), 2.0), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn block2_matches_requires_same_num_and_size() {
+        let block = Block2::new(3, true, 64);
+
+        assert!(block2_matches(&block, 3, 64));
+        assert!(!block2_matches(&block, 4, 64), "a different NUM must not match");
+        assert!(!block2_matches(&block, 3, 32), "a different size must not match");
+    }
+
+    fn observe_stream() -> ObserveStream {
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let sock = UdpSocket::bind(&local_addr).unwrap();
+        let transport = Box::new(UdpFramed::new(sock, CoapCodec)) as Box<Transport>;
+        let remote_addr = "127.0.0.1:1".parse().unwrap();
+
+        ObserveStream::new(
+            transport,
+            Message::new(),
+            generate_token(),
+            remote_addr,
+            TransportConfig { secure: false, security: Security::None },
+        )
+    }
+
+    #[test]
+    fn accept_sequence_accepts_the_first_value() {
+        let mut stream = observe_stream();
+
+        assert!(stream.accept_sequence(42));
+    }
+
+    #[test]
+    fn accept_sequence_rejects_stale_reordered_values() {
+        let mut stream = observe_stream();
+
+        assert!(stream.accept_sequence(10));
+        // RFC 7641 ยง3.4: a lower sequence number arriving shortly after is
+        // just network reordering, not a fresher notification.
+        assert!(!stream.accept_sequence(5));
+        assert!(stream.accept_sequence(11));
+    }
+
+    #[test]
+    fn accept_sequence_accepts_wraparound() {
+        let mut stream = observe_stream();
+
+        assert!(stream.accept_sequence((1 << 24) - 1));
+        // A small sequence number right after a value near the top of the
+        // 24-bit space is a wraparound, not staleness (RFC 7641 ยง3.4).
+        assert!(stream.accept_sequence(1));
+    }
+
+    #[test]
+    fn accept_sequence_rejects_large_jump_backward() {
+        let mut stream = observe_stream();
+
+        assert!(stream.accept_sequence(1 << 20));
+        // A large drop that isn't within the wraparound threshold is stale.
+        assert!(!stream.accept_sequence(1));
+    }
+}
 
 // This doesn't quite work, but leaving it here in case I want to fix & use it
 // in the future.
@@ -0,0 +1,92 @@
+use codec::CoapCodec;
+use client::IoFuture;
+use error::Error;
+use message::{Message, Code, Type as MessageType};
+
+use std::net::SocketAddr;
+
+use futures::prelude::*;
+use futures::sync::mpsc;
+
+use tokio::net::{UdpSocket, UdpFramed};
+use tokio::spawn;
+
+/// A CoAP endpoint that answers inbound requests rather than issuing them.
+///
+/// Unlike `Client`, which resolves a fresh `Endpoint` per request, a `Server`
+/// is bound once to a local address and then driven with a single handler
+/// for as long as it runs.
+pub struct Server {
+    local_addr: SocketAddr,
+}
+
+impl Server {
+    /// Prepare a server that will listen on `local_addr` once `serve` is
+    /// called.
+    pub fn bind(local_addr: SocketAddr) -> Server {
+        Server { local_addr }
+    }
+
+    /// Bind the socket and answer every inbound request with `handler`.
+    ///
+    /// `handler` receives the decomposed request `Message` (inspect its
+    /// Uri-Path options, per RFC 7252 ยง6.4, to route by resource path) and
+    /// returns the `Message` to reply with, or `None` to answer nothing yet.
+    /// The response's Token and Message ID are always overwritten with the
+    /// request's, so handlers don't need to manage them. A Confirmable
+    /// request answered with `None` still gets an empty ACK (RFC 7252
+    /// ยง4.2) so the client knows it was received.
+    ///
+    /// `handler` itself is called on the receiving task; the future it
+    /// returns is driven on its own spawned task, so a slow future only
+    /// delays that one reply, not the rest of the server.
+    pub fn serve<F>(self, mut handler: F) -> IoFuture<()>
+    where
+        F: FnMut(Message) -> IoFuture<::std::option::Option<Message>> + Send + 'static,
+    {
+        let sock = UdpSocket::bind(&self.local_addr).unwrap();
+        let (sink, stream) = UdpFramed::new(sock, CoapCodec).split();
+        let (tx, rx) = mpsc::unbounded();
+
+        let requests = stream.for_each(move |(msg, addr)| {
+            let id = msg.id;
+            let token = msg.token.clone();
+            let confirmable = msg.mtype == MessageType::Con;
+            let tx = tx.clone();
+
+            spawn(handler(msg).then(move |result| {
+                let response = match result {
+                    Ok(Some(mut reply)) => {
+                        reply.id = id;
+                        reply.token = token;
+                        Some(reply)
+                    },
+                    Ok(None) if confirmable => {
+                        let mut ack = Message::new();
+                        ack.mtype = MessageType::Ack;
+                        ack.code = Code::Empty;
+                        ack.id = id;
+                        Some(ack)
+                    },
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("request handler failed: {:?}", e);
+                        None
+                    },
+                };
+
+                if let Some(response) = response {
+                    let _ = tx.unbounded_send((response, addr));
+                }
+
+                Ok(())
+            }));
+
+            Ok(())
+        });
+
+        let replies = sink.send_all(rx.map_err(|()| Error::Timeout)).map(|_| ());
+
+        Box::new(requests.select(replies).map(|_| ()).map_err(|(e, _)| e))
+    }
+}